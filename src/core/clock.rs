@@ -0,0 +1,190 @@
+use std::fmt::Write as _;
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use evalexpr::{ContextWithMutableFunctions, EvalexprError, Function, HashMapContext, Value};
+use eyre::Result;
+
+/// Geographic coordinates used to derive `time.sunrise`/`time.sunset`.
+#[derive(Clone, Copy, Debug)]
+pub struct Location {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// Builds the `time.*` context variables for `now`.
+///
+/// Kept separate from `state_to_eval_context` so callers can apply it to an already-cached
+/// context without the current instant ever becoming part of the cache key.
+pub fn clock_eval_context_values(
+    now: DateTime<Utc>,
+    location: Option<Location>,
+) -> Vec<(String, Value)> {
+    let mut values = vec![
+        ("time.timestamp".to_string(), Value::Int(now.timestamp())),
+        ("time.hour".to_string(), Value::Int(now.hour() as i64)),
+        ("time.minute".to_string(), Value::Int(now.minute() as i64)),
+        (
+            "time.weekday".to_string(),
+            Value::Int(now.weekday().num_days_from_monday() as i64),
+        ),
+    ];
+
+    if let Some(location) = location {
+        let (sunrise, sunset) = sunrise::sunrise_sunset(
+            location.lat,
+            location.lon,
+            now.year(),
+            now.month(),
+            now.day(),
+        );
+
+        values.push(("time.sunrise".to_string(), Value::Int(sunrise)));
+        values.push(("time.sunset".to_string(), Value::Int(sunset)));
+    }
+
+    values
+}
+
+/// Parses `input` per `format`, defaulting to midnight when `format` has no time fields (e.g.
+/// `"%Y-%m-%d"`), since `NaiveDateTime::parse_from_str` alone errors with `NotEnough` on those.
+fn parse_naive_datetime(input: &str, format: &str) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::parse_from_str(input, format)
+        .or_else(|_| -> Result<NaiveDateTime, chrono::ParseError> {
+            Ok(NaiveDate::parse_from_str(input, format)?
+                .and_hms_opt(0, 0, 0)
+                .expect("midnight is always a valid time"))
+        })
+        .map_err(|err| err.to_string())
+}
+
+/// Registers `parse_time`, `parse_time_tz` and `format_time`. These are pure functions of their
+/// arguments, not of the current instant, so they're safe to register inside the cached
+/// `state_to_eval_context` rather than re-applied on every evaluation.
+pub fn register_clock_functions(context: &mut HashMapContext) -> Result<()> {
+    context.set_function(
+        "parse_time".into(),
+        Function::new(|argument| {
+            let arguments = argument.as_tuple()?;
+            let input = arguments[0].as_string()?;
+            let format = arguments[1].as_string()?;
+
+            let timestamp = parse_naive_datetime(&input, &format)
+                .map_err(|err| EvalexprError::CustomMessage(format!("parse_time: {err}")))?
+                .and_utc()
+                .timestamp();
+
+            Ok(Value::Int(timestamp))
+        }),
+    )?;
+
+    context.set_function(
+        "parse_time_tz".into(),
+        Function::new(|argument| {
+            let arguments = argument.as_tuple()?;
+            let input = arguments[0].as_string()?;
+            let format = arguments[1].as_string()?;
+            let tz_name = arguments[2].as_string()?;
+
+            let tz: Tz = tz_name.parse().map_err(|_| {
+                EvalexprError::CustomMessage(format!("parse_time_tz: unknown timezone {tz_name}"))
+            })?;
+
+            let naive = parse_naive_datetime(&input, &format)
+                .map_err(|err| EvalexprError::CustomMessage(format!("parse_time_tz: {err}")))?;
+
+            let timestamp = tz
+                .from_local_datetime(&naive)
+                .single()
+                .ok_or_else(|| {
+                    EvalexprError::CustomMessage(
+                        "parse_time_tz: ambiguous or nonexistent local time".into(),
+                    )
+                })?
+                .timestamp();
+
+            Ok(Value::Int(timestamp))
+        }),
+    )?;
+
+    context.set_function(
+        "format_time".into(),
+        Function::new(|argument| {
+            let arguments = argument.as_tuple()?;
+            let timestamp = arguments[0].as_int()?;
+            let format = arguments[1].as_string()?;
+
+            let datetime = DateTime::<Utc>::from_timestamp(timestamp, 0).ok_or_else(|| {
+                EvalexprError::CustomMessage(format!("format_time: invalid timestamp {timestamp}"))
+            })?;
+
+            // `to_string()` would panic on a malformed specifier (e.g. a trailing `%`) because
+            // chrono's `Display` impl returns `Err` rather than substituting anything; writing
+            // into a buffer ourselves lets us turn that into a regular `EvalexprError` instead.
+            let mut formatted = String::new();
+            write!(&mut formatted, "{}", datetime.format(&format)).map_err(|_| {
+                EvalexprError::CustomMessage(format!(
+                    "format_time: invalid format string {format:?}"
+                ))
+            })?;
+
+            Ok(Value::String(formatted))
+        }),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(context: &HashMapContext, expr: &str) -> Value {
+        evalexpr::eval_with_context(expr, context).unwrap()
+    }
+
+    #[test]
+    fn format_time_rejects_malformed_specifier_instead_of_panicking() {
+        let mut context = HashMapContext::new();
+        register_clock_functions(&mut context).unwrap();
+
+        let result = evalexpr::eval_with_context("format_time(0, \"%\")", &context);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn format_time_formats_a_valid_specifier() {
+        let mut context = HashMapContext::new();
+        register_clock_functions(&mut context).unwrap();
+
+        assert_eq!(
+            eval(&context, "format_time(0, \"%Y-%m-%d\")"),
+            Value::String("1970-01-01".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_time_defaults_a_date_only_format_to_midnight() {
+        let mut context = HashMapContext::new();
+        register_clock_functions(&mut context).unwrap();
+
+        assert_eq!(
+            eval(&context, "parse_time(\"1970-01-02\", \"%Y-%m-%d\")"),
+            Value::Int(86400)
+        );
+    }
+
+    #[test]
+    fn parse_time_accepts_a_full_datetime_format() {
+        let mut context = HashMapContext::new();
+        register_clock_functions(&mut context).unwrap();
+
+        assert_eq!(
+            eval(
+                &context,
+                "parse_time(\"1970-01-02 01:00:00\", \"%Y-%m-%d %H:%M:%S\")"
+            ),
+            Value::Int(90000)
+        );
+    }
+}
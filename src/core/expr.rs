@@ -4,18 +4,23 @@ use std::{
 };
 
 use cached::proc_macro::cached;
+use chrono::Utc;
 use evalexpr::*;
 use eyre::Result;
 use jsonptr::Assign;
+use log::warn;
 use serde_json_path::JsonPath;
 
+use super::clock::{clock_eval_context_values, register_clock_functions, Location};
 use crate::types::{
     action::Action,
     device::DevicesState,
+    environment::{EnvironmentDescriptor, EnvironmentId, EnvironmentOverlay, EnvironmentsConfig},
     event::{Message, TxEventChannel},
     group::{FlattenedGroupsConfig, GroupId},
     integration::{CustomActionDescriptor, IntegrationActionPayload, IntegrationId},
-    rule::{ForceTriggerRoutineDescriptor, RoutineId},
+    policy::RulePolicyConfig,
+    rule::{ForceTriggerRoutineDescriptor, RoutineId, RuleId},
     scene::{FlattenedScenesConfig, SceneDescriptor, SceneId},
 };
 
@@ -36,14 +41,8 @@ fn value_kv_pairs_deep(
                 value_kv_pairs_deep(value, key)
             })
             .collect(),
-        serde_json::Value::Array(array) => array
-            .iter()
-            .enumerate()
-            .flat_map(|(i, value)| {
-                let key = format!("{}.{}", prefix, i);
-                value_kv_pairs_deep(value, key)
-            })
-            .collect(),
+        // Arrays are kept whole and handed to `serde_value_to_evalexpr`, which maps them to a
+        // `Value::Tuple`, rather than being flattened into dotted index keys.
         _ => vec![(prefix, value.clone())],
     }
 }
@@ -52,13 +51,24 @@ fn serde_value_to_evalexpr(value: &serde_json::Value) -> Result<Value> {
     match value {
         serde_json::Value::Bool(b) => Ok(Value::Boolean(*b)),
         serde_json::Value::Number(n) => {
-            Ok(Value::Float(n.as_f64().ok_or_else(|| {
-                eyre!("Failed to convert serde number to evalexpr float")
-            })?))
+            if let Some(i) = n.as_i64() {
+                Ok(Value::Int(i))
+            } else {
+                // `n.as_u64()` would also catch integral numbers, but values above `i64::MAX`
+                // would wrap to negative when cast -- fall through to `Value::Float` instead.
+                Ok(Value::Float(n.as_f64().ok_or_else(|| {
+                    eyre!("Failed to convert serde number to evalexpr float")
+                })?))
+            }
         }
         serde_json::Value::String(s) => Ok(Value::String(s.clone())),
         serde_json::Value::Null => Ok(Value::Empty),
-        serde_json::Value::Array(_) => Err(eyre!("Arrays are not supported for rule evaluation")),
+        serde_json::Value::Array(array) => Ok(Value::Tuple(
+            array
+                .iter()
+                .map(serde_value_to_evalexpr)
+                .collect::<Result<Vec<_>>>()?,
+        )),
         serde_json::Value::Object(_) => Err(eyre!("Objects are not supported for rule evaluation")),
     }
 }
@@ -153,9 +163,141 @@ pub fn state_to_eval_context(
         })
     })?;
 
+    register_clock_functions(&mut context)?;
+    register_conversion_functions(&mut context)?;
+    register_list_functions(&mut context)?;
+
     Ok(context)
 }
 
+/// Registers `len`, `contains` and `nth` for the tuples produced from JSON arrays, so rules can
+/// inspect list-valued device/scene/group attributes without knowing their length up front.
+fn register_list_functions(context: &mut HashMapContext) -> Result<()> {
+    context.set_function(
+        "len".into(),
+        Function::new(|argument| {
+            let tuple = argument.as_tuple()?;
+            Ok(Value::Int(tuple.len() as i64))
+        }),
+    )?;
+
+    context.set_function(
+        "contains".into(),
+        Function::new(|argument| {
+            let arguments = argument.as_tuple()?;
+            let list = arguments[0].as_tuple()?;
+            let needle = &arguments[1];
+            Ok(Value::Boolean(list.iter().any(|item| item == needle)))
+        }),
+    )?;
+
+    context.set_function(
+        "nth".into(),
+        Function::new(|argument| {
+            let arguments = argument.as_tuple()?;
+            let list = arguments[0].as_tuple()?;
+            let index = arguments[1].as_int()?;
+
+            let item = usize::try_from(index)
+                .ok()
+                .and_then(|index| list.get(index))
+                .ok_or_else(|| {
+                    EvalexprError::CustomMessage(format!("nth: index {index} out of bounds"))
+                })?;
+
+            Ok(item.clone())
+        }),
+    )?;
+
+    Ok(())
+}
+
+/// Registers `to_int`, `to_float`, `to_bool` and `to_string`, letting rule authors normalize
+/// device state whose JSON type is inconsistent across integrations (e.g. brightness reported
+/// sometimes as `128` and sometimes as `128.0`).
+fn register_conversion_functions(context: &mut HashMapContext) -> Result<()> {
+    context.set_function(
+        "to_int".into(),
+        Function::new(|argument| match argument {
+            Value::Int(i) => Ok(Value::Int(*i)),
+            Value::Float(f) => Ok(Value::Int(*f as i64)),
+            Value::Boolean(b) => Ok(Value::Int(*b as i64)),
+            Value::String(s) => s
+                .parse::<i64>()
+                .map(Value::Int)
+                .map_err(|_| EvalexprError::CustomMessage(format!("to_int: cannot parse {s:?}"))),
+            other => Err(EvalexprError::CustomMessage(format!(
+                "to_int: unsupported value {other:?}"
+            ))),
+        }),
+    )?;
+
+    context.set_function(
+        "to_float".into(),
+        Function::new(|argument| match argument {
+            Value::Float(f) => Ok(Value::Float(*f)),
+            Value::Int(i) => Ok(Value::Float(*i as f64)),
+            Value::Boolean(b) => Ok(Value::Float(if *b { 1.0 } else { 0.0 })),
+            Value::String(s) => s
+                .parse::<f64>()
+                .map(Value::Float)
+                .map_err(|_| EvalexprError::CustomMessage(format!("to_float: cannot parse {s:?}"))),
+            other => Err(EvalexprError::CustomMessage(format!(
+                "to_float: unsupported value {other:?}"
+            ))),
+        }),
+    )?;
+
+    context.set_function(
+        "to_bool".into(),
+        Function::new(|argument| match argument {
+            Value::Boolean(b) => Ok(Value::Boolean(*b)),
+            Value::Int(i) => Ok(Value::Boolean(*i != 0)),
+            Value::Float(f) => Ok(Value::Boolean(*f != 0.0)),
+            Value::String(s) => s
+                .parse::<bool>()
+                .map(Value::Boolean)
+                .map_err(|_| EvalexprError::CustomMessage(format!("to_bool: cannot parse {s:?}"))),
+            other => Err(EvalexprError::CustomMessage(format!(
+                "to_bool: unsupported value {other:?}"
+            ))),
+        }),
+    )?;
+
+    context.set_function(
+        "to_string".into(),
+        Function::new(|argument| match argument {
+            Value::String(s) => Ok(Value::String(s.clone())),
+            other => Ok(Value::String(other.to_string())),
+        }),
+    )?;
+
+    Ok(())
+}
+
+/// Merges an environment's overrides into the base flattened scenes/groups config, with
+/// overlay entries taking precedence over base entries of the same ID. Called right where
+/// `FlattenedScenesConfig`/`FlattenedGroupsConfig` are built, before they're handed to
+/// `state_to_eval_context`, so an active environment actually changes what scenes/groups resolve
+/// to instead of only being visible as the `env.active` variable.
+fn apply_environment_overlay(
+    mut flattened_scenes: FlattenedScenesConfig,
+    mut flattened_groups: FlattenedGroupsConfig,
+    overlay: Option<&EnvironmentOverlay>,
+) -> (FlattenedScenesConfig, FlattenedGroupsConfig) {
+    if let Some(overlay) = overlay {
+        for (scene_id, scene) in overlay.scenes.0.clone() {
+            flattened_scenes.0.insert(scene_id, scene);
+        }
+
+        for (group_id, group) in overlay.groups.0.clone() {
+            flattened_groups.0.insert(group_id, group);
+        }
+    }
+
+    (flattened_scenes, flattened_groups)
+}
+
 fn tuple_value_to_vec_string(value: &Value) -> EvalexprResult<Vec<String>> {
     let tuple = value.as_tuple()?;
     let vec: Vec<String> = tuple
@@ -172,10 +314,33 @@ pub fn eval_action_expr(
     scenes: Scenes,
     groups: Groups,
     event_tx: &TxEventChannel,
+    clock_location: Option<Location>,
+    rule_id: &RuleId,
+    policy: &RulePolicyConfig,
+    active_environment: Option<&EnvironmentId>,
+    environments: &EnvironmentsConfig,
 ) -> Result<()> {
     let flattened_scenes = scenes.get_flattened_scenes(&devices);
     let flattened_groups = groups.get_flattened_groups(&devices);
+
+    let overlay =
+        active_environment.and_then(|environment_id| environments.overlay_for(environment_id));
+    let (flattened_scenes, flattened_groups) =
+        apply_environment_overlay(flattened_scenes, flattened_groups, overlay);
+
     let mut context = state_to_eval_context(devices.clone(), flattened_scenes, flattened_groups)?;
+
+    // `state_to_eval_context` is cached, so the clock variables are set here instead of inside
+    // it -- otherwise a cache hit would freeze `time.*` at whatever instant first populated it.
+    for (key, value) in clock_eval_context_values(Utc::now(), clock_location) {
+        context.set_value(key, value)?;
+    }
+
+    context.set_value(
+        "env.active".into(),
+        active_environment.map_or(Value::Empty, |env| Value::String(env.to_string())),
+    )?;
+
     context.set_type_safety_checks_disabled(true)?;
     let original_context = context.clone();
     let actions = Arc::new(RwLock::new(Vec::<EvalExprAction>::new()));
@@ -185,6 +350,7 @@ pub fn eval_action_expr(
         ActivateScene(SceneId),
         Custom(IntegrationId, IntegrationActionPayload),
         ForceTriggerRoutine(RoutineId),
+        ActivateEnvironment(EnvironmentId),
     }
 
     {
@@ -204,11 +370,23 @@ pub fn eval_action_expr(
 
     {
         let actions = actions.clone();
+        let rule_id = rule_id.clone();
+        let policy = policy.clone();
         context.set_function(
             "custom_action".into(),
             Function::new(move |argument| {
                 let arguments = argument.as_tuple()?;
-                let integration_id = arguments[0].as_string()?.into();
+                let integration_id: IntegrationId = arguments[0].as_string()?.into();
+
+                if !policy.allows_integration(&rule_id, &integration_id) {
+                    warn!(
+                        "Rule {rule_id} attempted custom_action against disallowed integration {integration_id}, skipping"
+                    );
+                    // Skip the dispatch but let the rest of the expression run, so one
+                    // disallowed call doesn't abort actions the rule already queued.
+                    return Ok(Value::Empty);
+                }
+
                 let payload = tuple_value_to_vec_string(&arguments[1])?.join("").into();
                 actions
                     .write()
@@ -221,11 +399,23 @@ pub fn eval_action_expr(
 
     {
         let actions = actions.clone();
+        let rule_id = rule_id.clone();
+        let policy = policy.clone();
         context.set_function(
             "trigger_routine".into(),
             Function::new(move |argument| {
                 let arguments = argument.as_tuple()?;
-                let routine_id = arguments[0].as_string()?.into();
+                let routine_id: RoutineId = arguments[0].as_string()?.into();
+
+                if !policy.allows_routine(&rule_id, &routine_id) {
+                    warn!(
+                        "Rule {rule_id} attempted to force-trigger disallowed routine {routine_id}, skipping"
+                    );
+                    // Skip the dispatch but let the rest of the expression run, so one
+                    // disallowed call doesn't abort actions the rule already queued.
+                    return Ok(Value::Empty);
+                }
+
                 actions
                     .write()
                     .unwrap()
@@ -235,6 +425,21 @@ pub fn eval_action_expr(
         )?;
     }
 
+    {
+        let actions = actions.clone();
+        context.set_function(
+            "activate_environment".into(),
+            Function::new(move |argument| {
+                let environment_id = argument.as_string()?.into();
+                actions
+                    .write()
+                    .unwrap()
+                    .push(EvalExprAction::ActivateEnvironment(environment_id));
+                Ok(Value::Empty)
+            }),
+        )?;
+    }
+
     let result = expr.eval_with_context_mut(&mut context)?;
 
     // Skip actions dispatch if expression evaluated to false
@@ -267,6 +472,9 @@ pub fn eval_action_expr(
             EvalExprAction::ForceTriggerRoutine(routine_id) => {
                 Action::ForceTriggerRoutine(ForceTriggerRoutineDescriptor { routine_id })
             }
+            EvalExprAction::ActivateEnvironment(environment_id) => {
+                Action::SetEnvironment(EnvironmentDescriptor { environment_id })
+            }
         };
 
         event_tx.send(Message::Action(action));
@@ -350,3 +558,93 @@ pub fn debug_print_context(context: &HashMapContext) {
 
     dbg!(&vars_sorted);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integral_json_numbers_become_evalexpr_ints() {
+        let value = serde_json::json!(128);
+        assert_eq!(serde_value_to_evalexpr(&value).unwrap(), Value::Int(128));
+    }
+
+    #[test]
+    fn fractional_json_numbers_become_evalexpr_floats() {
+        let value = serde_json::json!(128.5);
+        assert_eq!(
+            serde_value_to_evalexpr(&value).unwrap(),
+            Value::Float(128.5)
+        );
+    }
+
+    #[test]
+    fn u64_numbers_above_i64_max_become_evalexpr_floats_instead_of_wrapping() {
+        let value = serde_json::json!(u64::MAX);
+        assert_eq!(
+            serde_value_to_evalexpr(&value).unwrap(),
+            Value::Float(u64::MAX as f64)
+        );
+    }
+
+    #[test]
+    fn evalexpr_ints_round_trip_back_to_serde_integers() {
+        let value = evalexpr_value_to_serde(&Value::Int(128)).unwrap();
+        assert_eq!(value, serde_json::json!(128));
+        assert!(value.is_i64());
+    }
+
+    #[test]
+    fn json_arrays_become_evalexpr_tuples() {
+        let value = serde_json::json!(["movie", "reading"]);
+        assert_eq!(
+            serde_value_to_evalexpr(&value).unwrap(),
+            Value::Tuple(vec![
+                Value::String("movie".to_string()),
+                Value::String("reading".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn tuples_round_trip_back_to_serde_arrays() {
+        let value = Value::Tuple(vec![Value::Int(1), Value::Int(2)]);
+        assert_eq!(
+            evalexpr_value_to_serde(&value).unwrap(),
+            serde_json::json!([1, 2])
+        );
+    }
+
+    #[test]
+    fn list_functions_inspect_tuples() {
+        let mut context = HashMapContext::new();
+        register_list_functions(&mut context).unwrap();
+
+        context
+            .set_value(
+                "active_scenes".into(),
+                Value::Tuple(vec![
+                    Value::String("movie".to_string()),
+                    Value::String("reading".to_string()),
+                ]),
+            )
+            .unwrap();
+
+        assert_eq!(
+            evalexpr::eval_with_context("len(active_scenes)", &context).unwrap(),
+            Value::Int(2)
+        );
+        assert_eq!(
+            evalexpr::eval_with_context("contains(active_scenes, \"movie\")", &context).unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            evalexpr::eval_with_context("contains(active_scenes, \"sleeping\")", &context).unwrap(),
+            Value::Boolean(false)
+        );
+        assert_eq!(
+            evalexpr::eval_with_context("nth(active_scenes, 1)", &context).unwrap(),
+            Value::String("reading".to_string())
+        );
+    }
+}
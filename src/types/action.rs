@@ -3,6 +3,7 @@ use ts_rs::TS;
 
 use super::{
     dim::DimDescriptor,
+    environment::EnvironmentDescriptor,
     integration::CustomActionDescriptor,
     rule::ForceTriggerRoutineDescriptor,
     scene::{CycleScenesDescriptor, SceneDescriptor},
@@ -26,6 +27,9 @@ pub enum Action {
 
     /// Forcibly triggers a routine, ignoring any possible rules
     ForceTriggerRoutine(ForceTriggerRoutineDescriptor),
+
+    /// Switches the active environment/profile overlay (e.g. `home`, `vacation`, `guest`)
+    SetEnvironment(EnvironmentDescriptor),
 }
 
 pub type Actions = Vec<Action>;
@@ -0,0 +1,54 @@
+use std::{collections::HashMap, fmt};
+
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use super::{group::FlattenedGroupsConfig, scene::FlattenedScenesConfig};
+
+/// Identifies a named environment / profile overlay (e.g. `home`, `vacation`, `guest`) that
+/// scenes and groups can provide overrides for. Rules are not overlaid per environment.
+#[derive(TS, Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[ts(export)]
+pub struct EnvironmentId(pub String);
+
+impl fmt::Display for EnvironmentId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<String> for EnvironmentId {
+    fn from(s: String) -> Self {
+        EnvironmentId(s)
+    }
+}
+
+#[derive(TS, Clone, Debug, Deserialize, Serialize)]
+#[ts(export)]
+pub struct EnvironmentDescriptor {
+    pub environment_id: EnvironmentId,
+}
+
+/// Scene and group config an environment overlays on top of the base config when it's active.
+/// Entries here take precedence over the base config's entries with the same ID; entries not
+/// present in the overlay are left untouched.
+#[derive(TS, Clone, Debug, Default, Deserialize, Serialize)]
+#[ts(export)]
+pub struct EnvironmentOverlay {
+    #[serde(default)]
+    pub scenes: FlattenedScenesConfig,
+
+    #[serde(default)]
+    pub groups: FlattenedGroupsConfig,
+}
+
+/// Per-environment overlays, keyed by [`EnvironmentId`].
+#[derive(TS, Clone, Debug, Default, Deserialize, Serialize)]
+#[ts(export)]
+pub struct EnvironmentsConfig(pub HashMap<EnvironmentId, EnvironmentOverlay>);
+
+impl EnvironmentsConfig {
+    pub fn overlay_for(&self, environment_id: &EnvironmentId) -> Option<&EnvironmentOverlay> {
+        self.0.get(environment_id)
+    }
+}
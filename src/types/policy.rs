@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use super::{integration::IntegrationId, rule::RoutineId, rule::RuleId};
+
+/// Allowlist of dispatch targets a rule (or the config as a whole) is permitted to reach.
+///
+/// `None` means "no restriction"; `Some(vec![])` denies every target.
+#[derive(TS, Clone, Debug, Default, Deserialize, Serialize)]
+#[ts(export)]
+pub struct RuleActionPolicy {
+    /// Integration IDs this scope may target with `custom_action(integration_id, payload)`.
+    pub allowed_integrations: Option<Vec<IntegrationId>>,
+
+    /// Routine IDs this scope may force-trigger with `trigger_routine(routine_id)`.
+    pub allowed_routines: Option<Vec<RoutineId>>,
+}
+
+/// Bounds the blast radius of `custom_action` and `trigger_routine` calls made from rule
+/// expressions, combining a config-wide policy with optional per-rule overrides. A target must
+/// be allowed by both the global policy and the rule's own policy (when one is configured).
+#[derive(TS, Clone, Debug, Default, Deserialize, Serialize)]
+#[ts(export)]
+pub struct RulePolicyConfig {
+    #[serde(default)]
+    pub global: RuleActionPolicy,
+
+    #[serde(default)]
+    pub per_rule: HashMap<RuleId, RuleActionPolicy>,
+}
+
+impl RulePolicyConfig {
+    pub fn allows_integration(&self, rule_id: &RuleId, integration_id: &IntegrationId) -> bool {
+        let globally_allowed = self
+            .global
+            .allowed_integrations
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(integration_id));
+
+        let rule_allowed = self.per_rule.get(rule_id).is_none_or(|policy| {
+            policy
+                .allowed_integrations
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(integration_id))
+        });
+
+        globally_allowed && rule_allowed
+    }
+
+    pub fn allows_routine(&self, rule_id: &RuleId, routine_id: &RoutineId) -> bool {
+        let globally_allowed = self
+            .global
+            .allowed_routines
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(routine_id));
+
+        let rule_allowed = self.per_rule.get(rule_id).is_none_or(|policy| {
+            policy
+                .allowed_routines
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(routine_id))
+        });
+
+        globally_allowed && rule_allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_policy_allows_everything() {
+        let policy = RulePolicyConfig::default();
+        let rule_id: RuleId = "any_rule".to_string().into();
+        let integration_id: IntegrationId = "hue".to_string().into();
+        let routine_id: RoutineId = "good_morning".to_string().into();
+
+        assert!(policy.allows_integration(&rule_id, &integration_id));
+        assert!(policy.allows_routine(&rule_id, &routine_id));
+    }
+
+    #[test]
+    fn global_allowlist_rejects_targets_outside_it() {
+        let rule_id: RuleId = "any_rule".to_string().into();
+        let allowed: IntegrationId = "hue".to_string().into();
+        let disallowed: IntegrationId = "zigbee".to_string().into();
+
+        let policy = RulePolicyConfig {
+            global: RuleActionPolicy {
+                allowed_integrations: Some(vec![allowed.clone()]),
+                allowed_routines: None,
+            },
+            per_rule: HashMap::new(),
+        };
+
+        assert!(policy.allows_integration(&rule_id, &allowed));
+        assert!(!policy.allows_integration(&rule_id, &disallowed));
+    }
+
+    #[test]
+    fn per_rule_allowlist_is_scoped_to_its_rule() {
+        let strict_rule: RuleId = "strict_rule".to_string().into();
+        let other_rule: RuleId = "other_rule".to_string().into();
+        let routine_id: RoutineId = "vacation_mode".to_string().into();
+
+        let mut per_rule = HashMap::new();
+        per_rule.insert(
+            strict_rule.clone(),
+            RuleActionPolicy {
+                allowed_integrations: None,
+                allowed_routines: Some(vec![]),
+            },
+        );
+
+        let policy = RulePolicyConfig {
+            global: RuleActionPolicy::default(),
+            per_rule,
+        };
+
+        assert!(!policy.allows_routine(&strict_rule, &routine_id));
+        assert!(policy.allows_routine(&other_rule, &routine_id));
+    }
+
+    #[test]
+    fn target_must_be_allowed_by_both_global_and_per_rule_policy() {
+        let rule_id: RuleId = "scoped_rule".to_string().into();
+        let allowed_globally: IntegrationId = "hue".to_string().into();
+        let allowed_for_rule: IntegrationId = "zigbee".to_string().into();
+
+        let mut per_rule = HashMap::new();
+        per_rule.insert(
+            rule_id.clone(),
+            RuleActionPolicy {
+                allowed_integrations: Some(vec![allowed_for_rule.clone()]),
+                allowed_routines: None,
+            },
+        );
+
+        let policy = RulePolicyConfig {
+            global: RuleActionPolicy {
+                allowed_integrations: Some(vec![allowed_globally.clone()]),
+                allowed_routines: None,
+            },
+            per_rule,
+        };
+
+        // The two lists have no target in common, so neither target is allowed.
+        assert!(!policy.allows_integration(&rule_id, &allowed_globally));
+        assert!(!policy.allows_integration(&rule_id, &allowed_for_rule));
+    }
+}